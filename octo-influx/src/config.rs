@@ -0,0 +1,183 @@
+// Copyright 2022 the octopower authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+use std::fs;
+use std::path::PathBuf;
+
+use eyre::{eyre, Report, WrapErr};
+use influxdb::{Client, WriteQuery};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub octopus: OctopusConfig,
+    pub influxdb: WriteBackend,
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default = "default_num_readings")]
+    pub num_readings: usize,
+    #[serde(default = "default_num_readings")]
+    pub unit_rates_num_readings: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OctopusConfig {
+    pub email_address: String,
+    pub password: String,
+    pub account_id: String,
+}
+
+/// An MQTT broker to publish readings to, e.g. for Home Assistant.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_base_topic")]
+    pub base_topic: String,
+    #[serde(default)]
+    pub home_assistant_discovery: bool,
+}
+
+fn default_num_readings() -> usize {
+    100
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_base_topic() -> String {
+    "octopower".to_owned()
+}
+
+/// Which InfluxDB write API to target, and the credentials it needs.
+///
+/// Selected via a `version` tag in the `[influxdb]` config section, e.g.
+/// `version = "v1"` with `database`/`user`/`password`, or `version = "v2"`
+/// with `org`/`bucket`/`token`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "version", rename_all = "snake_case")]
+pub enum WriteBackend {
+    V1 {
+        url: String,
+        database: String,
+        user: Option<String>,
+        password: Option<String>,
+    },
+    V2 {
+        url: String,
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+impl Config {
+    pub fn from_file() -> Result<Config, Report> {
+        let config_path = Self::config_path()?;
+        let contents = fs::read_to_string(&config_path)
+            .wrap_err_with(|| format!("Failed to read config file {:?}", config_path))?;
+        let config = toml::from_str(&contents).wrap_err("Failed to parse config file")?;
+        Ok(config)
+    }
+
+    fn config_path() -> Result<PathBuf, Report> {
+        let mut path =
+            dirs::config_dir().ok_or_else(|| eyre!("Failed to find config directory"))?;
+        path.push("octopower");
+        path.push("config.toml");
+        Ok(path)
+    }
+}
+
+/// A handle to whichever InfluxDB write API was selected in the config.
+#[derive(Debug)]
+pub enum InfluxDbClient {
+    V1(Client),
+    V2 {
+        http: reqwest::Client,
+        url: String,
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+impl InfluxDbClient {
+    /// Write a batch of already-built v1 [`WriteQuery`] points, or their
+    /// line-protocol equivalent, depending on the selected backend.
+    ///
+    /// `queries` is used for the v1 API; `line_protocol` (one line per
+    /// point, newline-separated) is used for the v2 API.
+    pub async fn write(
+        &self,
+        queries: Vec<WriteQuery>,
+        line_protocol: &str,
+    ) -> Result<(), Report> {
+        match self {
+            InfluxDbClient::V1(client) => {
+                client
+                    .query(queries)
+                    .await
+                    .wrap_err("Failed to write points to InfluxDB v1")?;
+            }
+            InfluxDbClient::V2 {
+                http,
+                url,
+                org,
+                bucket,
+                token,
+            } => {
+                let response = http
+                    .post(format!("{}/api/v2/write", url))
+                    .query(&[("org", org.as_str()), ("bucket", bucket.as_str()), ("precision", "s")])
+                    .header("Authorization", format!("Token {}", token))
+                    .body(line_protocol.to_string())
+                    .send()
+                    .await
+                    .wrap_err("Failed to write points to InfluxDB v2")?;
+                let status = response.status();
+                if !status.is_success() {
+                    let body = response.text().await.unwrap_or_default();
+                    return Err(eyre!("InfluxDB v2 write failed ({}): {}", status, body));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn get_influxdb_client(backend: &WriteBackend) -> Result<InfluxDbClient, Report> {
+    match backend {
+        WriteBackend::V1 {
+            url,
+            database,
+            user,
+            password,
+        } => {
+            let mut client = Client::new(url, database);
+            if let (Some(user), Some(password)) = (user, password) {
+                client = client.with_auth(user, password);
+            }
+            Ok(InfluxDbClient::V1(client))
+        }
+        WriteBackend::V2 {
+            url,
+            org,
+            bucket,
+            token,
+        } => Ok(InfluxDbClient::V2 {
+            http: reqwest::Client::new(),
+            url: url.clone(),
+            org: org.clone(),
+            bucket: bucket.clone(),
+            token: token.clone(),
+        }),
+    }
+}
@@ -2,32 +2,122 @@
 // This project is dual-licensed under Apache 2.0 and MIT terms.
 // See LICENSE-APACHE and LICENSE-MIT for details.
 
-use chrono::{DateTime, Utc};
-use eyre::Report;
-use influxdb::{Client, WriteQuery};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use eyre::{Report, WrapErr};
+use influxdb::WriteQuery;
 use influxdb::InfluxDbWriteable;
-use log::info;
+use log::{error, info, warn};
 use regex::Regex;
 
 use config::{Config, get_influxdb_client};
+use mqtt::MqttSink;
 use octopower::{
     authenticate, AuthToken, get_account, get_consumption, MeterType, get_standard_unit_rates,
-    results::consumption::Consumption,
-    results::standing_unit_rate::StandingUnitRate
 };
+use sink::{InfluxSink, Sink};
+use state::ImportState;
+use writer::{IntoPoint, Writer};
 
 mod config;
+mod mqtt;
+mod sink;
+mod state;
+mod writer;
+
+/// How often `--daemon` mode polls when no `--interval` is given.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1800);
+/// Re-authenticate once the auth token is within this long of expiring.
+const TOKEN_REFRESH_MARGIN: ChronoDuration = ChronoDuration::minutes(5);
+/// Readings are settled half-hourly; flag a meter as stale if it hasn't
+/// produced a new reading within this long.
+const STALE_AFTER: ChronoDuration = ChronoDuration::hours(6);
+
+struct CliArgs {
+    daemon: bool,
+    interval: Duration,
+}
+
+fn parse_args() -> CliArgs {
+    let mut args = CliArgs {
+        daemon: false,
+        interval: DEFAULT_POLL_INTERVAL,
+    };
+    for arg in std::env::args().skip(1) {
+        if arg == "--daemon" {
+            args.daemon = true;
+        } else if let Some(secs) = arg.strip_prefix("--interval=") {
+            if let Ok(secs) = secs.parse::<u64>() {
+                args.interval = Duration::from_secs(secs);
+            } else {
+                warn!("Ignoring unparseable --interval value {:?}", secs);
+            }
+        }
+    }
+    args
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Report> {
     pretty_env_logger::init();
 
+    let args = parse_args();
     let config = Config::from_file()?;
     let influxdb_client = get_influxdb_client(&config.influxdb)?;
-    println!("************************");
-    println!(" influxdb_client = {:?}", influxdb_client);
-    let token = authenticate(&config.octopus.email_address, &config.octopus.password).await?;
-    let account = get_account(&token, &config.octopus.account_id).await?;
+    let writer = Writer::spawn(influxdb_client);
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(InfluxSink(writer))];
+    if let Some(mqtt_config) = &config.mqtt {
+        sinks.push(Box::new(MqttSink::connect(mqtt_config).await?));
+    }
+    let mut state = ImportState::load()?;
+
+    if args.daemon {
+        info!("Starting in daemon mode, polling every {:?}", args.interval);
+        let mut token = authenticate(&config.octopus.email_address, &config.octopus.password).await?;
+        let mut ticker = tokio::time::interval(args.interval);
+        loop {
+            ticker.tick().await;
+            if token_needs_refresh(&token) {
+                info!("Auth token nearing expiry, re-authenticating");
+                token = authenticate(&config.octopus.email_address, &config.octopus.password).await?;
+            }
+            if let Err(err) = run_import_cycle(&token, &config, &sinks, &mut state).await {
+                error!("Import cycle failed: {:#}", err);
+            }
+            // Each import already synced its own writes and only advanced its
+            // watermark on success (see import_consumption_readings/
+            // import_unit_rates), so whatever's in `state` at this point is
+            // safe to persist.
+            if let Err(err) = state.save() {
+                error!("Failed to persist import state: {:#}", err);
+            }
+        }
+    } else {
+        let token = authenticate(&config.octopus.email_address, &config.octopus.password).await?;
+        run_import_cycle(&token, &config, &sinks, &mut state).await?;
+        for sink in sinks {
+            sink.flush().await?;
+        }
+        state.save()?;
+        Ok(())
+    }
+}
+
+/// `AuthToken` carries its own expiry; refresh a little early so a long-lived
+/// daemon never races a request against the token expiring mid-flight.
+fn token_needs_refresh(token: &AuthToken) -> bool {
+    Utc::now() + TOKEN_REFRESH_MARGIN >= token.expires_at
+}
+
+/// Run one full pass over the account: import consumption and unit rates for
+/// every meter, then emit a health point summarising the run.
+async fn run_import_cycle(token: &AuthToken, config: &Config, sinks: &[Box<dyn Sink>], state: &mut ImportState) -> Result<(), Report> {
+    let cycle_start = Instant::now();
+    let mut records_imported: i64 = 0;
+    let mut api_errors: i64 = 0;
+
+    let account = get_account(token, &config.octopus.account_id).await?;
 
     let mut tariff_code: &str = "";
     let mut product_code: &str = "";
@@ -43,16 +133,30 @@ async fn main() -> Result<(), Report> {
             }
             for meter in &electricity_meter_point.meters {
                 info!("Meter serial {}", meter.serial_number);
-                import_consumption_readings(&token, MeterType::Electricity, &electricity_meter_point.mpan,
-                    &meter.serial_number, &influxdb_client, "consumption", config.num_readings) .await?;
+                match import_consumption_readings(token, MeterType::Electricity, &electricity_meter_point.mpan,
+                    &meter.serial_number, sinks, state, "consumption", config.num_readings).await {
+                    Ok(num_queued) => records_imported += num_queued as i64,
+                    Err(err) => {
+                        error!("Failed to import electricity consumption for {}: {:#}", meter.serial_number, err);
+                        api_errors += 1;
+                    }
+                }
+                report_staleness(state, MeterType::Electricity, &electricity_meter_point.mpan, &meter.serial_number, sinks).await?;
             }
         }
         for gas_meter_point in &property.gas_meter_points {
             info!("Gas MPRN {}", gas_meter_point.mprn);
             for meter in &gas_meter_point.meters {
                 info!("Meter serial {}", meter.serial_number);
-                import_consumption_readings(&token, MeterType::Gas, &gas_meter_point.mprn, &meter.serial_number,
-                    &influxdb_client, "consumption", config.num_readings).await?;
+                match import_consumption_readings(token, MeterType::Gas, &gas_meter_point.mprn, &meter.serial_number,
+                    sinks, state, "consumption", config.num_readings).await {
+                    Ok(num_queued) => records_imported += num_queued as i64,
+                    Err(err) => {
+                        error!("Failed to import gas consumption for {}: {:#}", meter.serial_number, err);
+                        api_errors += 1;
+                    }
+                }
+                report_staleness(state, MeterType::Gas, &gas_meter_point.mprn, &meter.serial_number, sinks).await?;
             }
         }
 
@@ -66,87 +170,314 @@ async fn main() -> Result<(), Report> {
             product_code = &tariff_code[captured.start()..captured.end()];
         }
         info!("Extracted product code : {}", product_code);
-        import_unit_rates(&token, product_code, tariff_code, &influxdb_client, "rates", config.unit_rates_num_readings).await?;
+        match import_unit_rates(token, product_code, tariff_code, sinks, state, "rates", config.unit_rates_num_readings).await {
+            Ok(num_queued) => records_imported += num_queued as i64,
+            Err(err) => {
+                error!("Failed to import unit rates for {}: {:#}", tariff_code, err);
+                api_errors += 1;
+            }
+        }
     }
 
+    let write_latency_ms = cycle_start.elapsed().as_millis() as i64;
+    info!("Import cycle complete: {} records, {} errors, {}ms", records_imported, api_errors, write_latency_ms);
+    let health = HealthPoint {
+        time: Utc::now(),
+        records_imported,
+        api_errors,
+        write_latency_ms,
+    };
+    for sink in sinks {
+        sink.write_health(&health).await?;
+    }
+    Ok(())
+}
+
+/// Record a `meter_status` point for `meter_type`/`mpxn`/`serial`, with
+/// `stale=1` (and a warning) if it hasn't produced a new consumption reading
+/// within [`STALE_AFTER`], else `stale=0`.
+async fn report_staleness(state: &ImportState, meter_type: MeterType, mpxn: &str, serial: &str, sinks: &[Box<dyn Sink>]) -> Result<(), Report> {
+    // Compare against when we last *saw* a new reading, not the reading's own
+    // `interval_start` — Octopus's settlement lag means the newest available
+    // interval is routinely hours old even for a perfectly healthy meter.
+    let stale = state
+        .consumption_last_seen(meter_type, mpxn, serial)
+        .is_some_and(|last_seen| Utc::now() - last_seen > STALE_AFTER);
+    if stale {
+        warn!("{:?} meter {} ({}) has had no new consumption for over {}", meter_type, serial, mpxn, STALE_AFTER);
+    }
+    let status = MeterStatus {
+        time: Utc::now(),
+        meter_type: meter_type.to_string(),
+        mpxn: mpxn.to_string(),
+        serial: serial.to_string(),
+        stale: stale as i64,
+    };
+    for sink in sinks {
+        sink.write_meter_status(&status).await?;
+    }
+    Ok(())
+}
+
+/// Flush every sink and confirm all of them wrote durably.
+async fn sync_sinks(sinks: &[Box<dyn Sink>]) -> Result<(), Report> {
+    for sink in sinks {
+        sink.sync().await?;
+    }
     Ok(())
 }
 
 async fn import_consumption_readings(token: &AuthToken, meter_type: MeterType, mpxn: &str,
-    serial: &str, influxdb_client: &Client, measurement: &str, num_readings: usize) -> Result<(), Report> {
+    serial: &str, sinks: &[Box<dyn Sink>], state: &mut ImportState, measurement: &str, num_readings: usize) -> Result<usize, Report> {
+    let watermark = state.consumption_watermark(meter_type, mpxn, serial);
+    // Pass the watermark as `period_from` so the API itself only returns
+    // readings newer than what we've already ingested, instead of us
+    // re-fetching and re-filtering the same page every cycle. The filter
+    // below is kept as a safety net in case the API's `period_from` bound is
+    // inclusive of the watermark itself.
     let consumption =
-        get_consumption(token, meter_type, mpxn, serial, 0, num_readings, None).await?;
+        get_consumption(token, meter_type, mpxn, serial, 0, num_readings, watermark).await?;
     info!("{:?} consumption: {}/{} records", meter_type, consumption.results.len(), consumption.count);
-    let points = consumption
-        .results
-        .into_iter()
-        .map(|reading| get_consumption_write_query(measurement, meter_type, mpxn, serial, reading))
-        .collect::<Vec<WriteQuery>>();
-
-    let result = influxdb_client.query(points).await;
-    info!("Writing consumption data to influxdb == {:?}", result);
-    assert!(result.is_ok(), "Write result was not okay");
-    Ok(())
+
+    let mut latest = watermark;
+    let mut num_queued = 0;
+    for reading in consumption.results {
+        if watermark.is_some_and(|watermark| reading.interval_start <= watermark) {
+            continue;
+        }
+        latest = Some(latest.map_or(reading.interval_start, |l| l.max(reading.interval_start)));
+        let point = ConsumptionReading {
+            time: reading.interval_start,
+            meter_type: meter_type.to_string(),
+            mpxn: mpxn.to_string(),
+            serial: serial.to_string(),
+            consumption: reading.consumption as f64
+        };
+        for sink in sinks {
+            sink.write_consumption(measurement, &point).await?;
+        }
+        num_queued += 1;
+    }
+    if num_queued > 0 {
+        // Confirm the points above actually landed before trusting them
+        // enough to advance the watermark past them — `write_consumption`
+        // only enqueues into the Writer's channel, it doesn't wait for the
+        // HTTP write.
+        sync_sinks(sinks)
+            .await
+            .wrap_err("Failed to durably write consumption readings; not advancing watermark")?;
+        state.set_consumption_last_seen(meter_type, mpxn, serial, Utc::now());
+    }
+    if let Some(latest) = latest {
+        state.set_consumption_watermark(meter_type, mpxn, serial, latest);
+    }
+    info!("Queued {} new consumption points for writing", num_queued);
+    Ok(num_queued)
 }
 
 async fn import_unit_rates(token: &AuthToken, product_code: &str, tariff_code: &str,
-    influxdb_client: &Client, measurement: &str, num_readings: usize) -> Result<(), Report> {
+    sinks: &[Box<dyn Sink>], state: &mut ImportState, measurement: &str, num_readings: usize) -> Result<usize, Report> {
+    let watermark = state.rate_watermark(tariff_code);
+    // Unlike `get_consumption`, `octopower::get_standard_unit_rates` has no
+    // `period_from`/since parameter at all, so there's no way to ask the API
+    // for only the rates newer than `watermark` — this rescopes that part of
+    // the request to write-side dedup only, via the filtering below.
     let rates = get_standard_unit_rates(token, MeterType::Electricity, product_code, tariff_code, 0, num_readings).await?;
     info!("{:?} rates: {}/{} records", MeterType::Electricity, rates.results.len(), rates.count);
-    let points = rates
-        .results
-        .into_iter()
-        .map(|rate| get_unit_rates_write_query(measurement, product_code, tariff_code, rate))
-        .collect::<Vec<WriteQuery>>();
-
-    let result = influxdb_client.query(points).await;
-    info!("Writing unit rate data to influxdb == {:?}", result);
-    assert!(result.is_ok(), "Write result was not okay");
-    Ok(())
+
+    let mut latest = watermark;
+    let mut num_queued = 0;
+    for rate in rates.results {
+        if watermark.is_some_and(|watermark| rate.valid_from <= watermark) {
+            continue;
+        }
+        latest = Some(latest.map_or(rate.valid_from, |l| l.max(rate.valid_from)));
+        let point = UnitRatesReading {
+            time: rate.valid_from,
+            product_code: product_code.to_string(),
+            tariff_code: tariff_code.to_string(),
+            rate: rate.value_inc_vat as f64
+        };
+        for sink in sinks {
+            sink.write_rate(measurement, &point).await?;
+        }
+        num_queued += 1;
+    }
+    if num_queued > 0 {
+        sync_sinks(sinks)
+            .await
+            .wrap_err("Failed to durably write unit rates; not advancing watermark")?;
+    }
+    if let Some(latest) = latest {
+        state.set_rate_watermark(tariff_code, latest);
+    }
+    info!("Queued {} new unit rate points for writing", num_queued);
+    Ok(num_queued)
+}
+
+/// Escape a measurement name for line protocol: commas and spaces delimit
+/// the measurement from tags/fields, so both must be backslash-escaped.
+fn escape_measurement(value: &str) -> String {
+    value.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+/// Escape a tag key or value for line protocol: commas and spaces are
+/// element delimiters and `=` separates a tag's key from its value.
+fn escape_tag(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+#[derive(Clone, InfluxDbWriteable)]
+pub(crate) struct HealthPoint {
+    time: DateTime<Utc>,
+    records_imported: i64,
+    api_errors: i64,
+    write_latency_ms: i64,
+}
+
+impl IntoPoint for HealthPoint {
+    fn to_write_query(&self, measurement: &str) -> WriteQuery {
+        self.clone().into_query(measurement)
+    }
+
+    fn to_line_protocol(&self, measurement: &str) -> String {
+        format!(
+            "{} records_imported={}i,api_errors={}i,write_latency_ms={}i {}",
+            escape_measurement(measurement),
+            self.records_imported,
+            self.api_errors,
+            self.write_latency_ms,
+            self.time.timestamp()
+        )
+    }
+}
+
+#[derive(Clone, InfluxDbWriteable)]
+pub(crate) struct MeterStatus {
+    time: DateTime<Utc>,
+    #[influxdb(tag)] meter_type: String,
+    #[influxdb(tag)] mpxn: String,
+    #[influxdb(tag)] serial: String,
+    stale: i64,
+}
+
+impl IntoPoint for MeterStatus {
+    fn to_write_query(&self, measurement: &str) -> WriteQuery {
+        self.clone().into_query(measurement)
+    }
+
+    fn to_line_protocol(&self, measurement: &str) -> String {
+        format!(
+            "{},meter_type={},mpxn={},serial={} stale={}i {}",
+            escape_measurement(measurement),
+            escape_tag(&self.meter_type),
+            escape_tag(&self.mpxn),
+            escape_tag(&self.serial),
+            self.stale,
+            self.time.timestamp()
+        )
+    }
 }
 
-#[derive(InfluxDbWriteable)]
-struct ConsumptionReading {
+#[derive(Clone, InfluxDbWriteable)]
+pub(crate) struct ConsumptionReading {
     time: DateTime<Utc>,
     #[influxdb(tag)] meter_type: String,
     #[influxdb(tag)] mpxn: String,
     #[influxdb(tag)] serial: String,
     consumption: f64
 }
-fn get_consumption_write_query(
-    measurement: &str,
-    meter_type: MeterType,
-    mpxn: &str,
-    serial: &str,
-    consumption: Consumption
-) -> WriteQuery {
-    ConsumptionReading {
-        time: consumption.interval_start,
-        meter_type: meter_type.to_string(),
-        mpxn: mpxn.to_string(),
-        serial: serial.to_string(),
-        consumption: consumption.consumption as f64
-    }.into_query(measurement)
+
+impl ConsumptionReading {
+    /// Render as a single InfluxDB line protocol line for the v2 write API.
+    fn to_line_protocol(&self, measurement: &str) -> String {
+        format!(
+            "{},meter_type={},mpxn={},serial={} consumption={} {}",
+            escape_measurement(measurement),
+            escape_tag(&self.meter_type),
+            escape_tag(&self.mpxn),
+            escape_tag(&self.serial),
+            self.consumption,
+            self.time.timestamp()
+        )
+    }
+}
+
+impl IntoPoint for ConsumptionReading {
+    fn to_write_query(&self, measurement: &str) -> WriteQuery {
+        self.clone().into_query(measurement)
+    }
+
+    fn to_line_protocol(&self, measurement: &str) -> String {
+        ConsumptionReading::to_line_protocol(self, measurement)
+    }
 }
 
-#[derive(InfluxDbWriteable)]
-struct UnitRatesReading {
+#[derive(Clone, InfluxDbWriteable)]
+pub(crate) struct UnitRatesReading {
     time: DateTime<Utc>,
     #[influxdb(tag)] product_code: String,
     #[influxdb(tag)] tariff_code: String,
     rate: f64
 }
 
-fn get_unit_rates_write_query(
-    measurement: &str,
-    product_code: &str,
-    tariff_code: &str,
-    unit_rate: StandingUnitRate
-) -> WriteQuery {
-    UnitRatesReading {
-        time: unit_rate.valid_from,
-        product_code: product_code.to_string(),
-        tariff_code: tariff_code.to_string(),
-        rate: unit_rate.value_inc_vat as f64
-    }.into_query(measurement)
+impl UnitRatesReading {
+    /// Render as a single InfluxDB line protocol line for the v2 write API.
+    fn to_line_protocol(&self, measurement: &str) -> String {
+        format!(
+            "{},product_code={},tariff_code={} rate={} {}",
+            escape_measurement(measurement),
+            escape_tag(&self.product_code),
+            escape_tag(&self.tariff_code),
+            self.rate,
+            self.time.timestamp()
+        )
+    }
+}
+
+impl IntoPoint for UnitRatesReading {
+    fn to_write_query(&self, measurement: &str) -> WriteQuery {
+        self.clone().into_query(measurement)
+    }
+
+    fn to_line_protocol(&self, measurement: &str) -> String {
+        UnitRatesReading::to_line_protocol(self, measurement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumption_reading_line_protocol_escapes_tag_values() {
+        let reading = ConsumptionReading {
+            time: DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z").unwrap().with_timezone(&Utc),
+            meter_type: "Electricity".to_owned(),
+            mpxn: "1234567890".to_owned(),
+            serial: "serial, with space=oddity".to_owned(),
+            consumption: 1.5,
+        };
+        assert_eq!(
+            reading.to_line_protocol("consumption"),
+            "consumption,meter_type=Electricity,mpxn=1234567890,serial=serial\\,\\ with\\ space\\=oddity consumption=1.5 1672531200"
+        );
+    }
+
+    #[test]
+    fn unit_rates_reading_line_protocol_escapes_measurement() {
+        let reading = UnitRatesReading {
+            time: DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z").unwrap().with_timezone(&Utc),
+            product_code: "VAR-22-11-01".to_owned(),
+            tariff_code: "E-1R-VAR-22-11-01-A".to_owned(),
+            rate: 28.34,
+        };
+        assert_eq!(
+            reading.to_line_protocol("unit rates"),
+            "unit\\ rates,product_code=VAR-22-11-01,tariff_code=E-1R-VAR-22-11-01-A rate=28.34 1672531200"
+        );
+    }
 }
@@ -0,0 +1,197 @@
+// Copyright 2022 the octopower authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use eyre::{eyre, Report, WrapErr};
+use log::warn;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, Transport};
+use serde_json::json;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::config::MqttConfig;
+use crate::sink::Sink;
+use crate::{ConsumptionReading, HealthPoint, MeterStatus, UnitRatesReading};
+
+/// How long [`MqttSink::sync`] and [`MqttSink::flush`] wait for outstanding
+/// QoS-1 publishes to be acknowledged: `sync` then fails, `flush` disconnects
+/// anyway.
+const FLUSH_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Publishes readings to an MQTT broker as retained JSON messages, with an
+/// optional Home Assistant MQTT-discovery config message per meter so
+/// sensors auto-register.
+pub struct MqttSink {
+    client: AsyncClient,
+    base_topic: String,
+    home_assistant_discovery: bool,
+    announced: Mutex<HashSet<String>>,
+    /// Number of QoS-1 publishes sent but not yet acknowledged by the
+    /// broker. [`MqttSink::flush`] waits for this to reach zero before
+    /// disconnecting, so buffered publishes aren't lost when the event-loop
+    /// task is torn down.
+    pending_acks: Arc<AtomicUsize>,
+    event_loop_task: JoinHandle<()>,
+}
+
+impl MqttSink {
+    pub async fn connect(config: &MqttConfig) -> Result<MqttSink, Report> {
+        let mut options = MqttOptions::new("octopower", config.host.clone(), config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+        if config.tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+
+        let (client, mut event_loop) = AsyncClient::new(options, 10);
+        let pending_acks = Arc::new(AtomicUsize::new(0));
+        let event_loop_task = tokio::spawn({
+            let pending_acks = pending_acks.clone();
+            async move {
+                loop {
+                    match event_loop.poll().await {
+                        Ok(Event::Incoming(Packet::PubAck(_))) => {
+                            pending_acks.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            warn!("MQTT connection error: {:#}", err);
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(MqttSink {
+            client,
+            base_topic: config.base_topic.clone(),
+            home_assistant_discovery: config.home_assistant_discovery,
+            announced: Mutex::new(HashSet::new()),
+            pending_acks,
+            event_loop_task,
+        })
+    }
+
+    async fn publish(&self, topic: &str, payload: serde_json::Value) -> Result<(), Report> {
+        self.pending_acks.fetch_add(1, Ordering::SeqCst);
+        let result = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, true, payload.to_string().into_bytes())
+            .await
+            .wrap_err_with(|| format!("Failed to publish to MQTT topic {}", topic));
+        if result.is_err() {
+            // Never actually made it onto the wire, so no PubAck will arrive
+            // to balance this out.
+            self.pending_acks.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Publish a Home Assistant discovery config for a meter the first time
+    /// it's seen; a no-op on subsequent calls.
+    async fn announce_consumption_sensor(&self, reading: &ConsumptionReading) -> Result<(), Report> {
+        let unique_id = format!("octopower_{}_{}", reading.mpxn, reading.serial);
+        {
+            let mut announced = self.announced.lock().await;
+            if !announced.insert(unique_id.clone()) {
+                return Ok(());
+            }
+        }
+
+        let is_gas = reading.meter_type == "Gas";
+        let config_topic = format!("homeassistant/sensor/{}/config", unique_id);
+        let payload = json!({
+            "name": format!("Octopus {} consumption ({})", reading.meter_type, reading.serial),
+            "unique_id": unique_id,
+            "state_topic": format!("{}/{}/{}/consumption", self.base_topic, reading.mpxn, reading.serial),
+            "value_template": "{{ value_json.value }}",
+            "unit_of_measurement": if is_gas { "m³" } else { "kWh" },
+            "device_class": if is_gas { "gas" } else { "energy" },
+            // Each point is a single half-hour interval's consumption, not a
+            // running meter total, so this isn't a monotonic counter.
+            "state_class": "measurement",
+        });
+        self.publish(&config_topic, payload).await
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    async fn write_consumption(&self, _measurement: &str, reading: &ConsumptionReading) -> Result<(), Report> {
+        let topic = format!("{}/{}/{}/consumption", self.base_topic, reading.mpxn, reading.serial);
+        let payload = json!({
+            "time": reading.time.to_rfc3339(),
+            "value": reading.consumption,
+            "unit": if reading.meter_type == "Gas" { "m3" } else { "kWh" },
+        });
+        self.publish(&topic, payload).await?;
+
+        if self.home_assistant_discovery {
+            self.announce_consumption_sensor(reading).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_rate(&self, _measurement: &str, reading: &UnitRatesReading) -> Result<(), Report> {
+        let topic = format!("{}/rates/{}", self.base_topic, reading.tariff_code);
+        let payload = json!({
+            "time": reading.time.to_rfc3339(),
+            "value": reading.rate,
+            "unit": "GBP/kWh",
+        });
+        self.publish(&topic, payload).await
+    }
+
+    async fn write_health(&self, _point: &HealthPoint) -> Result<(), Report> {
+        Ok(())
+    }
+
+    async fn write_meter_status(&self, _point: &MeterStatus) -> Result<(), Report> {
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<(), Report> {
+        let deadline = tokio::time::Instant::now() + FLUSH_ACK_TIMEOUT;
+        while self.pending_acks.load(Ordering::SeqCst) > 0 {
+            if tokio::time::Instant::now() >= deadline {
+                return Err(eyre!(
+                    "Timed out waiting for {} MQTT publish(es) to be acknowledged",
+                    self.pending_acks.load(Ordering::SeqCst)
+                ));
+            }
+            tokio::time::sleep(FLUSH_POLL_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    async fn flush(self: Box<Self>) -> Result<(), Report> {
+        let deadline = tokio::time::Instant::now() + FLUSH_ACK_TIMEOUT;
+        while self.pending_acks.load(Ordering::SeqCst) > 0 {
+            if tokio::time::Instant::now() >= deadline {
+                warn!(
+                    "Timed out waiting for {} MQTT publish(es) to be acknowledged; disconnecting anyway",
+                    self.pending_acks.load(Ordering::SeqCst)
+                );
+                break;
+            }
+            tokio::time::sleep(FLUSH_POLL_INTERVAL).await;
+        }
+
+        self.client
+            .disconnect()
+            .await
+            .wrap_err("Failed to disconnect MQTT client")?;
+        self.event_loop_task.abort();
+        Ok(())
+    }
+}
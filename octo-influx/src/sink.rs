@@ -0,0 +1,69 @@
+// Copyright 2022 the octopower authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+use async_trait::async_trait;
+use eyre::Report;
+
+use crate::writer::Writer;
+use crate::{ConsumptionReading, HealthPoint, MeterStatus, UnitRatesReading};
+
+/// A destination for imported readings.
+///
+/// `InfluxSink` and `MqttSink` both implement this so `main` can write each
+/// reading to whichever backends the config enables, without caring which.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    async fn write_consumption(&self, measurement: &str, reading: &ConsumptionReading) -> Result<(), Report>;
+    async fn write_rate(&self, measurement: &str, reading: &UnitRatesReading) -> Result<(), Report>;
+
+    /// Record a per-run health/staleness point. Only meaningful for sinks
+    /// that back a metrics dashboard; other sinks can ignore it.
+    async fn write_health(&self, _point: &HealthPoint) -> Result<(), Report> {
+        Ok(())
+    }
+    async fn write_meter_status(&self, _point: &MeterStatus) -> Result<(), Report> {
+        Ok(())
+    }
+
+    /// Flush everything written so far and confirm it landed durably.
+    /// Callers should call this before trusting that a point has been
+    /// written — e.g. before advancing an incremental-import watermark past
+    /// it — and must not advance past points this returns an error for.
+    async fn sync(&self) -> Result<(), Report> {
+        Ok(())
+    }
+
+    /// Flush any buffered points and shut the sink down cleanly.
+    async fn flush(self: Box<Self>) -> Result<(), Report>;
+}
+
+/// Writes readings to InfluxDB via the batched, retrying [`Writer`].
+pub struct InfluxSink(pub Writer);
+
+#[async_trait]
+impl Sink for InfluxSink {
+    async fn write_consumption(&self, measurement: &str, reading: &ConsumptionReading) -> Result<(), Report> {
+        self.0.send(measurement, Box::new(reading.clone())).await
+    }
+
+    async fn write_rate(&self, measurement: &str, reading: &UnitRatesReading) -> Result<(), Report> {
+        self.0.send(measurement, Box::new(reading.clone())).await
+    }
+
+    async fn write_health(&self, point: &HealthPoint) -> Result<(), Report> {
+        self.0.send("health", Box::new(point.clone())).await
+    }
+
+    async fn write_meter_status(&self, point: &MeterStatus) -> Result<(), Report> {
+        self.0.send("meter_status", Box::new(point.clone())).await
+    }
+
+    async fn sync(&self) -> Result<(), Report> {
+        self.0.sync().await
+    }
+
+    async fn flush(self: Box<Self>) -> Result<(), Report> {
+        self.0.flush().await
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2022 the octopower authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+use std::time::Duration;
+
+use eyre::{eyre, Report, WrapErr};
+use influxdb::WriteQuery;
+use log::warn;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::config::InfluxDbClient;
+
+/// Points are flushed once a batch reaches this size...
+const BATCH_SIZE: usize = 5000;
+/// ...or on each tick of this fixed interval, whichever is first.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A reading that can be turned into either write API's point representation.
+pub trait IntoPoint: Send {
+    fn to_write_query(&self, measurement: &str) -> WriteQuery;
+    fn to_line_protocol(&self, measurement: &str) -> String;
+}
+
+struct QueuedPoint {
+    measurement: String,
+    point: Box<dyn IntoPoint>,
+}
+
+enum Message {
+    Point(QueuedPoint),
+    /// Flush whatever's buffered right now and report back whether it was
+    /// written durably, without shutting the writer down.
+    Sync(oneshot::Sender<Result<(), Report>>),
+}
+
+/// A buffered, batched, retrying writer to InfluxDB.
+///
+/// Points are sent over a bounded channel and written by a background task in
+/// batches of [`BATCH_SIZE`] (or every [`FLUSH_INTERVAL`], whichever comes
+/// first), so a slow or flaky InfluxDB no longer means unbounded memory growth
+/// or a panic on the first transient error.
+///
+/// A batch that still fails after retrying is not silently dropped: callers
+/// that need to know a point has actually landed before acting on that (e.g.
+/// persisting an incremental-import watermark past it) should call
+/// [`Writer::sync`], which flushes everything buffered so far and reports
+/// whether it succeeded.
+pub struct Writer {
+    tx: mpsc::Sender<Message>,
+    handle: JoinHandle<()>,
+}
+
+impl Writer {
+    pub fn spawn(client: InfluxDbClient) -> Writer {
+        let (tx, rx) = mpsc::channel(BATCH_SIZE * 2);
+        let handle = tokio::spawn(run(client, rx));
+        Writer { tx, handle }
+    }
+
+    /// Queue a point for writing. Returns an error if the writer task has
+    /// already stopped (e.g. because it panicked).
+    pub async fn send(&self, measurement: &str, point: Box<dyn IntoPoint>) -> Result<(), Report> {
+        self.tx
+            .send(Message::Point(QueuedPoint {
+                measurement: measurement.to_owned(),
+                point,
+            }))
+            .await
+            .map_err(|_| eyre!("InfluxDB writer task is no longer running"))
+    }
+
+    /// Flush everything queued so far and wait for the result, without
+    /// shutting the writer down. Call this before trusting that a point has
+    /// been durably written, e.g. before advancing an incremental-import
+    /// watermark past it.
+    pub async fn sync(&self) -> Result<(), Report> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tx
+            .send(Message::Sync(reply_tx))
+            .await
+            .map_err(|_| eyre!("InfluxDB writer task is no longer running"))?;
+        reply_rx
+            .await
+            .map_err(|_| eyre!("InfluxDB writer task is no longer running"))?
+    }
+
+    /// Stop accepting new points, flush everything buffered, and wait for the
+    /// writer task to finish. Returns an error if any batch since the last
+    /// [`Writer::sync`] could not be written after retrying.
+    pub async fn flush(self) -> Result<(), Report> {
+        let result = self.sync().await;
+        drop(self.tx);
+        self.handle
+            .await
+            .map_err(|join_err| eyre!("InfluxDB writer task panicked: {}", join_err))?;
+        result
+    }
+}
+
+async fn run(client: InfluxDbClient, mut rx: mpsc::Receiver<Message>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    interval.tick().await; // first tick fires immediately
+    // A batch dropped by a size- or interval-triggered flush since the last
+    // sync; the next sync reports it instead of succeeding.
+    let mut unsynced_failure: Option<Report> = None;
+
+    loop {
+        tokio::select! {
+            received = rx.recv() => {
+                match received {
+                    Some(Message::Point(point)) => {
+                        batch.push(point);
+                        if batch.len() >= BATCH_SIZE {
+                            if let Err(err) = flush_batch(&client, &mut batch).await {
+                                warn!("{:#}", err);
+                                unsynced_failure = Some(err);
+                            }
+                        }
+                    }
+                    Some(Message::Sync(reply)) => {
+                        let result = flush_batch(&client, &mut batch).await;
+                        let result = match unsynced_failure.take() {
+                            Some(err) => Err(err),
+                            None => result,
+                        };
+                        let _ = reply.send(result);
+                    }
+                    None => {
+                        if let Err(err) = flush_batch(&client, &mut batch).await {
+                            warn!("{:#}", err);
+                        }
+                        break;
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                if let Err(err) = flush_batch(&client, &mut batch).await {
+                    warn!("{:#}", err);
+                    unsynced_failure = Some(err);
+                }
+            }
+        }
+    }
+}
+
+async fn flush_batch(client: &InfluxDbClient, batch: &mut Vec<QueuedPoint>) -> Result<(), Report> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let points = std::mem::replace(batch, Vec::with_capacity(BATCH_SIZE));
+    let num_points = points.len();
+
+    let mut backoff = INITIAL_BACKOFF;
+    let mut last_err = None;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let queries = points
+            .iter()
+            .map(|p| p.point.to_write_query(&p.measurement))
+            .collect::<Vec<WriteQuery>>();
+        let line_protocol = points
+            .iter()
+            .map(|p| p.point.to_line_protocol(&p.measurement))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        match client.write(queries, &line_protocol).await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < MAX_ATTEMPTS => {
+                warn!(
+                    "InfluxDB write failed (attempt {}/{}), retrying in {:?}: {:#}",
+                    attempt, MAX_ATTEMPTS, backoff, err
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                last_err = Some(err);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| eyre!("unknown write failure")))
+        .wrap_err_with(|| format!("Dropped batch of {} points after {} failed attempts", num_points, MAX_ATTEMPTS))
+}